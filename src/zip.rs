@@ -5,7 +5,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::iters::{SIMDIterator, SIMDIterable, SIMDObject, UnsafeIterator, SIMDSized};
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+
+use crate::iters::{SIMDIterator, SIMDIterable, SIMDObject, UnsafeIterator, SIMDSized, IntoSIMDIterator};
 use crate::vecs::{Packed, Packable};
 
 /// A macro which takes a number n and an expression, and returns a tuple
@@ -39,8 +42,60 @@ use crate::vecs::{Packed, Packable};
 
 /// A lazy iterator which returns tuples of the elements of its contained
 /// iterators.
+///
+/// Iterates in lockstep with `itertools::multizip` semantics: if the
+/// contained iterators report differing lengths, `Zip` stops once the
+/// shortest one is exhausted rather than panicking.
 pub struct Zip<T> {
-    iters: T
+    iters: T,
+    /// The scalar length of the shortest contained iterator, captured when
+    /// the members were zipped together.
+    len: usize,
+    /// The scalar position of the back cursor used by `DoubleEndedIterator`.
+    /// Forward consumption must not pass this, and backward consumption
+    /// must not pass `scalar_pos()`.
+    back_pos: usize,
+}
+
+/// A lazy iterator which returns tuples of the elements of its contained
+/// iterators, padding exhausted members with their default vector.
+///
+/// Mirrors `itertools::zip_longest`: iteration continues until the longest
+/// contained iterator is exhausted, substituting `default()` for the lanes
+/// of any member which ran out first.
+pub struct ZipLongest<T> {
+    iters: T,
+    /// The scalar length of the longest contained iterator, captured when
+    /// the members were zipped together.
+    len: usize,
+    /// The true scalar length of each contained iterator, captured when the
+    /// members were zipped together (indexed the same as the `iters` tuple).
+    ///
+    /// A member's `len()` may shrink as it is advanced (`SIMDRange`'s does),
+    /// while padding decisions need the fixed total, so it's cached here
+    /// once instead of being re-derived mid-iteration.
+    lens: Vec<usize>,
+}
+
+/// A lazy SIMD iterator which yields consecutive index vectors
+/// `[pos, pos + 1, ..., pos + width - 1]` of a chosen integer lane type.
+///
+/// Pairs with [`SIMDEnumerate::simd_enumerate`] to give SIMD kernels access
+/// to the scalar position of each lane alongside its data, e.g. for gather
+/// offsets or position-dependent masking.
+pub struct SIMDRange<V> {
+    pos: usize,
+    len: usize,
+    _vector: PhantomData<V>,
+}
+
+impl<V> SIMDRange<V> where V : Packed {
+    /// Create a counting iterator which yields `len` consecutive scalar
+    /// indices, starting at zero, packed into vectors of `V`.
+    #[inline(always)]
+    pub fn new(len: usize) -> Self {
+        SIMDRange { pos: 0, len: len, _vector: PhantomData }
+    }
 }
 
 /// A lazy mapping iterator which applies its function to a stream of tuples of
@@ -53,7 +108,48 @@ pub struct SIMDZipMap<I, F> where I : SIMDZippedIterator {
 /// A trait which can transform a collection of iterators into a `Zip`
 pub trait IntoSIMDZip : Sized {
     /// Return an iterator which may iterate over `self` in lockstep.
+    ///
+    /// If the members have differing scalar lengths, the returned `Zip`
+    /// stops once the shortest member is exhausted, mirroring
+    /// `itertools::multizip`.
     fn zip(self) -> Zip<Self>;
+
+    /// Return an iterator which iterates over `self` in lockstep until the
+    /// longest member is exhausted, filling the lanes of any shorter member
+    /// with its default vector once it runs out.
+    ///
+    /// This lets SIMD kernels run over ragged inputs without pre-padding
+    /// them to a common length.
+    fn zip_longest(self) -> ZipLongest<Self>;
+}
+
+/// A trait implemented on tuples of slices (or mutable slices) which can be
+/// packed directly into a `Zip`, given a tuple of per-lane default vectors.
+///
+/// This is what powers the free function [`multizip`](fn.multizip.html); you
+/// should generally prefer calling that over this trait directly.
+pub trait IntoSIMDZipSlices<D> : Sized {
+    /// The tuple of SIMD iterators produced by packing `self`.
+    type Zipped;
+
+    /// Pack `self` into SIMD iterators using `defaults`, then zip them.
+    fn multizip(self, defaults: D) -> Zip<Self::Zipped>;
+}
+
+/// Zip slices (or mutable slices) directly into a `Zip`, packing each one
+/// into a SIMD iterator with its corresponding default vector before zipping
+/// them together.
+///
+/// Equivalent to calling `.simd_iter(default)` on every operand and then
+/// `.zip()`-ing the results, collapsed into a single call:
+///
+/// ```rust,ignore
+/// let zipped = multizip((&a[..], &b[..]), (f32s(0.0), f32s(0.0)));
+/// ```
+#[inline(always)]
+pub fn multizip<S, D>(slices: S, defaults: D) -> Zip<S::Zipped>
+    where S : IntoSIMDZipSlices<D> {
+    slices.multizip(defaults)
 }
 
 pub trait SIMDZippedObject : Sized {
@@ -218,17 +314,160 @@ pub trait SIMDZippedIterator : SIMDZippedIterable {
         }
         start
     }
+
+    /// Return a vector generated by reducing `func` over accumulator `start`
+    /// and the values of this iterator in reverse, starting from the back.
+    ///
+    /// Unlike [`simd_reduce`], which visits the partial tail vector (if any)
+    /// last, this visits it first, with the remaining full-width vectors
+    /// visited in descending order. Mixing this with forward consumption of the same iterator is
+    /// unsupported; the two cursors will refuse to cross but the elements
+    /// seen by each call still depend on how much of the other end was
+    /// already consumed.
+    ///
+    /// [`simd_reduce`]: #tymethod.simd_reduce
+    #[inline(always)]
+    fn simd_rreduce<A, F>(&mut self, mut start: A, mut func: F) -> A
+        where F : FnMut(A, Self::Vectors) -> A, Self : DoubleEndedIterator<Item = Self::Vectors> {
+
+        while let Some(v) = self.next_back() {
+            start = func(start, v);
+        }
+        start
+    }
+}
+
+/// Extends [`UnsafeIterator`] with an unchecked accessor for reading full
+/// vectors from the back, which `Zip`'s `DoubleEndedIterator` impl needs to
+/// walk its members in descending order.
+pub trait UnsafeReverseIterator : UnsafeIterator {
+    /// Unsafely pack and return the full vector starting at `pos`, read from
+    /// the back of the iterator. `pos` is always a valid offset for a
+    /// complete vector, just like in [`UnsafeIterator::next_unchecked`].
+    unsafe fn next_back_unchecked(&self, pos: usize) -> Self::Vector;
+}
+
+impl<V> SIMDObject for SIMDRange<V> where V : Packed {
+    type Vector = V;
+    type Scalar = V::Scalar;
+}
+
+impl<V> SIMDSized for SIMDRange<V> where V : Packed {
+    #[inline(always)]
+    fn scalar_len(&self) -> usize {
+        self.len
+    }
 }
 
+impl<V> SIMDIterable for SIMDRange<V> where V : Packed, V::Scalar : From<u32> {
+    #[inline(always)]
+    fn scalar_pos(&self) -> usize {
+        self.pos
+    }
+
+    #[inline(always)]
+    fn advance(&mut self, amount: usize) {
+        self.pos += amount;
+    }
+
+    #[inline(always)]
+    fn default(&self) -> Self::Vector {
+        V::default()
+    }
+}
+
+impl<V> ExactSizeIterator for SIMDRange<V> where V : Packed, V::Scalar : From<u32> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.len - self.pos
+    }
+}
+
+impl<V> Iterator for SIMDRange<V> where V : Packed, V::Scalar : From<u32> {
+    type Item = V;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos + self.width() > self.len {
+            return None;
+        }
+        let ret = unsafe { self.next_unchecked(self.pos) };
+        self.advance(self.width());
+        Some(ret)
+    }
+}
+
+impl<V> SIMDIterator for SIMDRange<V> where V : Packed, V::Scalar : From<u32> {
+    #[inline(always)]
+    fn end(&mut self) -> Option<(Self::Vector, usize)> {
+        let n = self.len - self.pos;
+        if n == 0 {
+            return None;
+        }
+        let ret = unsafe { self.end_unchecked(self.pos, n) };
+        self.advance(n);
+        Some((ret, n))
+    }
+}
+
+impl<V> UnsafeIterator for SIMDRange<V> where V : Packed, V::Scalar : From<u32> {
+    #[inline(always)]
+    unsafe fn next_unchecked(&self, pos: usize) -> Self::Vector {
+        V::generate(|i| <V::Scalar as From<u32>>::from((pos + i) as u32))
+    }
+
+    #[inline(always)]
+    unsafe fn end_unchecked(&self, pos: usize, n: usize) -> Self::Vector {
+        V::generate(|i| if i < n {
+            <V::Scalar as From<u32>>::from((pos + i) as u32)
+        } else {
+            V::Scalar::default()
+        })
+    }
+}
+
+// `pos` is an absolute scalar offset, so reading the full vector at `pos` from
+// the back is the same read as `next_unchecked(pos)` for every member.
+impl<T> UnsafeReverseIterator for T where T : UnsafeIterator {
+    #[inline(always)]
+    unsafe fn next_back_unchecked(&self, pos: usize) -> Self::Vector {
+        self.next_unchecked(pos)
+    }
+}
+
+/// A trait providing [`simd_enumerate`](#tymethod.simd_enumerate) on any SIMD
+/// iterator, pairing it with the scalar position of each lane.
+pub trait SIMDEnumerate : SIMDIterator + UnsafeIterator + Sized {
+    /// Zip `self` with a lazily-generated index vector, so that kernels
+    /// receive `(index_vector, data_vector)` tuples instead of bare data.
+    ///
+    /// `V` is the integer lane type used for the indices (e.g. `u32s`); pick
+    /// one wide enough to hold the largest scalar position you expect to
+    /// iterate over.
+    #[inline(always)]
+    fn simd_enumerate<V>(self) -> Zip<(SIMDRange<V>, Self)>
+        where V : Packed, V::Scalar : From<u32>,
+              (SIMDRange<V>, Self) : IntoSIMDZip {
+        (SIMDRange::new(self.len()), self).zip()
+    }
+}
+
+impl<T> SIMDEnumerate for T where T : SIMDIterator + UnsafeIterator {}
+
 macro_rules! impl_iter_zip {
     (($($a:tt),*), ($($b:tt),*), ($($n:tt),*)) => (
         impl<$($a),*> IntoSIMDZip for ($($a),*) where $($a : SIMDIterator + UnsafeIterator),* {
             #[inline(always)]
             fn zip(self) -> Zip<Self> {
-                if $(self.0.len() != self.$n.len())||* {
-                    panic!("You can only zip iterators of the same length.");
-                }
-                Zip { iters: self }
+                let min_len = [self.0.len(), $(self.$n.len()),*].iter().cloned().min().unwrap();
+                Zip { iters: self, len: min_len, back_pos: min_len }
+            }
+
+            #[inline(always)]
+            fn zip_longest(self) -> ZipLongest<Self> {
+                let lens = vec![self.0.len(), $(self.$n.len()),*];
+                let max_len = lens.iter().cloned().max().unwrap();
+                ZipLongest { iters: self, len: max_len, lens: lens }
             }
         }
 
@@ -236,7 +475,7 @@ macro_rules! impl_iter_zip {
             where $($a : SIMDIterator + UnsafeIterator),* {
             #[inline(always)]
             fn len(&self) -> usize {
-                self.iters.0.len()
+                self.back_pos - self.iters.0.scalar_pos()
             }
         }
 
@@ -247,9 +486,55 @@ macro_rules! impl_iter_zip {
             #[inline(always)]
             fn next(&mut self) -> Option<<Self as SIMDZippedObject>::Vectors> {
                 let pos = self.iters.0.scalar_pos();
-                self.iters.0.next().map(|v| unsafe {
-                    (v, $(self.iters.$n.next_unchecked(pos)),*)
-                })
+                if pos + self.width() > self.back_pos {
+                    return None;
+                }
+                let ret = unsafe {
+                    (self.iters.0.next_unchecked(pos), $(self.iters.$n.next_unchecked(pos)),*)
+                };
+                self.advance(self.width());
+                Some(ret)
+            }
+
+            #[inline(always)]
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                let remaining = <Self as ExactSizeIterator>::len(self);
+                (remaining, Some(remaining))
+            }
+        }
+
+        impl<$($a),*> FusedIterator for Zip<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {}
+
+        impl<$($a),*> DoubleEndedIterator for Zip<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator + UnsafeReverseIterator),* {
+
+            #[inline(always)]
+            fn next_back(&mut self) -> Option<<Self as SIMDZippedObject>::Vectors> {
+                let width = self.width();
+                let tail = self.len % width;
+
+                if tail != 0 && self.back_pos == self.len {
+                    let pos = self.len - tail;
+                    if pos < self.iters.0.scalar_pos() {
+                        return None;
+                    }
+                    let ret = unsafe {
+                        (self.iters.0.end_unchecked(pos, tail), $(self.iters.$n.end_unchecked(pos, tail)),*)
+                    };
+                    self.back_pos = pos;
+                    return Some(ret);
+                }
+
+                if self.back_pos < width || self.back_pos - width < self.iters.0.scalar_pos() {
+                    return None;
+                }
+                let pos = self.back_pos - width;
+                let ret = unsafe {
+                    (self.iters.0.next_back_unchecked(pos), $(self.iters.$n.next_back_unchecked(pos)),*)
+                };
+                self.back_pos = pos;
+                Some(ret)
             }
         }
 
@@ -275,9 +560,15 @@ macro_rules! impl_iter_zip {
             #[inline(always)]
             fn end(&mut self) -> Option<(Self::Vectors, usize)> {
                 let pos = self.iters.0.scalar_pos();
-                self.iters.0.end().map(|(v, n)| unsafe {
-                    ((v, $(self.iters.$n.end_unchecked(pos, n)),*), n)
-                })
+                let n = self.back_pos - pos;
+                if n == 0 {
+                    return None;
+                }
+                let ret = unsafe {
+                    (self.iters.0.end_unchecked(pos, n), $(self.iters.$n.end_unchecked(pos, n)),*)
+                };
+                self.advance(n);
+                Some((ret, n))
             }
         }
 
@@ -292,6 +583,98 @@ macro_rules! impl_iter_zip {
             #[inline(always)]
             fn advance(&mut self, amount: usize) {
                 self.iters.0.advance(amount);
+                $(self.iters.$n.advance(amount);)*
+            }
+
+            #[inline(always)]
+            fn default(&self) -> Self::Vectors {
+                (self.iters.0.default(), $(self.iters.$n.default()),*)
+            }
+        }
+
+        impl<$($a),*> ExactSizeIterator for ZipLongest<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {
+            #[inline(always)]
+            fn len(&self) -> usize {
+                self.len - self.iters.0.scalar_pos()
+            }
+        }
+
+        impl<$($a),*> Iterator for ZipLongest<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {
+            type Item = ($(<$a as Iterator>::Item),*);
+
+            #[inline(always)]
+            fn next(&mut self) -> Option<<Self as SIMDZippedObject>::Vectors> {
+                let pos = self.iters.0.scalar_pos();
+                if pos + self.width() > self.len {
+                    return None;
+                }
+                let width = self.width();
+                let ret = unsafe {
+                    (
+                        if pos + width <= self.lens[0] { self.iters.0.next_unchecked(pos) }
+                        else if pos < self.lens[0] { self.iters.0.end_unchecked(pos, self.lens[0] - pos) }
+                        else { self.iters.0.default() },
+                        $(if pos + width <= self.lens[$n] { self.iters.$n.next_unchecked(pos) }
+                          else if pos < self.lens[$n] { self.iters.$n.end_unchecked(pos, self.lens[$n] - pos) }
+                          else { self.iters.$n.default() }),*
+                    )
+                };
+                self.advance(width);
+                Some(ret)
+            }
+        }
+
+        impl<$($a),*> SIMDZippedObject for ZipLongest<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {
+            type Vectors = ($($a::Vector),*);
+            type Scalars = ($($a::Scalar),*);
+
+            #[inline(always)]
+            fn width(&self) -> usize {
+                self.iters.0.width()
+            }
+
+            #[inline(always)]
+            fn size(&self) -> usize {
+                self.iters.0.size()
+            }
+        }
+
+        impl<$($a),*> SIMDZippedIterator for ZipLongest<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {
+
+            #[inline(always)]
+            fn end(&mut self) -> Option<(Self::Vectors, usize)> {
+                let pos = self.iters.0.scalar_pos();
+                let n = self.len - pos;
+                if n == 0 {
+                    return None;
+                }
+                let ret = unsafe {
+                    (
+                        if pos >= self.lens[0] { self.iters.0.default() } else { self.iters.0.end_unchecked(pos, core::cmp::min(n, self.lens[0] - pos)) },
+                        $(if pos >= self.lens[$n] { self.iters.$n.default() } else { self.iters.$n.end_unchecked(pos, core::cmp::min(n, self.lens[$n] - pos)) }),*
+                    )
+                };
+                self.advance(n);
+                Some((ret, n))
+            }
+        }
+
+        impl<$($a),*> SIMDZippedIterable for ZipLongest<($($a),*)>
+            where $($a : SIMDIterator + UnsafeIterator),* {
+
+            #[inline(always)]
+            fn scalar_pos(&self) -> usize {
+                self.iters.0.scalar_pos()
+            }
+
+            #[inline(always)]
+            fn advance(&mut self, amount: usize) {
+                self.iters.0.advance(amount);
+                $(self.iters.$n.advance(amount);)*
             }
 
             #[inline(always)]
@@ -299,6 +682,18 @@ macro_rules! impl_iter_zip {
                 (self.iters.0.default(), $(self.iters.$n.default()),*)
             }
         }
+
+        impl<$($a),*, $($b),*> IntoSIMDZipSlices<($($b),*)> for ($($a),*)
+            where $($a : IntoSIMDIterator<Vector = $b>),*,
+                  $($a::Iter : SIMDIterator + UnsafeIterator),*,
+                  ($($a::Iter),*) : IntoSIMDZip {
+            type Zipped = ($($a::Iter),*);
+
+            #[inline(always)]
+            fn multizip(self, defaults: ($($b),*)) -> Zip<Self::Zipped> {
+                (self.0.simd_iter(defaults.0), $(self.$n.simd_iter(defaults.$n)),*).zip()
+            }
+        }
     );
 }
 
@@ -310,8 +705,17 @@ impl<I, F, A> Iterator for SIMDZipMap<I, F>
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(&mut self.func)
     }
+
+    #[inline(always)]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.iter.len();
+        (remaining, Some(remaining))
+    }
 }
 
+impl<I, F, A> FusedIterator for SIMDZipMap<I, F>
+    where I : SIMDZippedIterator + FusedIterator, F : FnMut(I::Vectors) -> A, A : Packed {}
+
 impl<I, F, A> ExactSizeIterator for SIMDZipMap<I, F>
     where I : SIMDZippedIterator, F : FnMut(I::Vectors) -> A, A : Packed {
     #[inline(always)]
@@ -406,3 +810,95 @@ impl_iter_zip!((A, B, C, D, E, F, G, H, I, J, K, L),
 impl_iter_zip!((A, B, C, D, E, F, G, H, I, J, K, L, M),
                (AA, BB, CC, DD, EE, FF, GG, HH, II, JJ, KK, LL, MM),
                (1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vecs::{f32s, u32s};
+
+    #[test]
+    fn zip_truncates_to_shortest_and_packs_partial_tail() {
+        let a = [1.0f32; 100];
+        let b = [2.0f32; 97];
+        let mut zipped = (a[..].simd_iter(f32s(0.0)), b[..].simd_iter(f32s(0.0))).zip();
+
+        let mut consumed = 0;
+        while zipped.next().is_some() {
+            consumed += zipped.width();
+        }
+
+        let (_, n) = zipped.end().expect("a partial tail vector for the 97-element member");
+        consumed += n;
+
+        assert_eq!(consumed, 97);
+    }
+
+    #[test]
+    fn zip_longest_pads_the_shorter_member_with_default_past_its_real_tail() {
+        let a = [1.0f32; 100];
+        let b = [2.0f32; 50];
+        let mut zipped = (a[..].simd_iter(f32s(0.0)), b[..].simd_iter(f32s(0.0))).zip_longest();
+
+        // Once b runs out, its lanes should be padded with 0.0 rather than
+        // dropping the real elements it still had left, so this should sum
+        // to exactly 50 real 2.0s and nothing more.
+        let b_total = zipped.simd_reduce(f32s(0.0), |acc, (_, bv)| acc + bv).sum();
+
+        assert_eq!(b_total, 100.0);
+    }
+
+    #[test]
+    fn zip_forward_iteration_stops_at_elements_already_taken_from_the_back() {
+        let a = [1.0f32; 160];
+        let b = [2.0f32; 160];
+        let mut zipped = (a[..].simd_iter(f32s(0.0)), b[..].simd_iter(f32s(0.0))).zip();
+
+        assert!(zipped.next_back().is_some());
+        let back_consumed = zipped.width();
+
+        let mut forward_consumed = 0;
+        while zipped.next().is_some() {
+            forward_consumed += zipped.width();
+        }
+        if let Some((_, n)) = zipped.end() {
+            forward_consumed += n;
+        }
+
+        // Forward iteration must not be able to re-read what next_back()
+        // already consumed from the tail.
+        assert_eq!(forward_consumed, 160 - back_consumed);
+    }
+
+    #[test]
+    fn simd_rreduce_sums_every_element_in_descending_order() {
+        let a = [3.0f32; 100];
+        let b = [4.0f32; 100];
+        let mut zipped = (a[..].simd_iter(f32s(0.0)), b[..].simd_iter(f32s(0.0))).zip();
+
+        let total = zipped.simd_rreduce(f32s(0.0), |acc, (av, _)| acc + av).sum();
+
+        assert_eq!(total, 300.0);
+    }
+
+    #[test]
+    fn multizip_packs_slices_directly_without_a_separate_simd_iter_call() {
+        let a = [5.0f32; 64];
+        let b = [6.0f32; 64];
+
+        let mut zipped = multizip((&a[..], &b[..]), (f32s(0.0), f32s(0.0)));
+
+        let total = zipped.simd_reduce(f32s(0.0), |acc, (av, bv)| acc + av + bv).sum();
+
+        assert_eq!(total, 64.0 * (5.0 + 6.0));
+    }
+
+    #[test]
+    fn simd_enumerate_pairs_each_vector_with_its_scalar_index() {
+        let a = [1.0f32; 64];
+        let mut zipped = a[..].simd_iter(f32s(0.0)).simd_enumerate::<u32s>();
+
+        let index_total = zipped.simd_reduce(u32s(0), |acc, (idx, _)| acc + idx).sum();
+
+        assert_eq!(index_total, (0..64u32).sum());
+    }
+}